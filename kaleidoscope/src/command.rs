@@ -0,0 +1,192 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::FocusError;
+
+/// A single, typed Focus command: something that knows how to serialize
+/// itself to the wire format Focus expects, and how to parse its own reply
+/// into a structured value, instead of pushing that work onto every caller
+/// of [`Focus::request`](crate::Focus::request).
+pub trait Command {
+    /// The structured value this command's reply parses into.
+    type Response;
+
+    /// The bare command name, as sent over the wire (e.g. `"version"`).
+    fn name(&self) -> &str;
+
+    /// Any arguments to send after the command name.
+    fn args(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Parses a cleaned-up reply (sentinel and blank lines already
+    /// stripped by [`Focus::read_reply`](crate::Focus::read_reply)) into
+    /// this command's response type.
+    fn parse(&self, reply: String) -> Result<Self::Response, FocusError>;
+}
+
+/// `version` -- asks the device for its firmware version string.
+pub struct Version;
+
+impl Command for Version {
+    type Response = String;
+
+    fn name(&self) -> &str {
+        "version"
+    }
+
+    fn parse(&self, reply: String) -> Result<Self::Response, FocusError> {
+        Ok(reply)
+    }
+}
+
+/// `help` -- lists every Focus command the device supports.
+pub struct Help;
+
+impl Command for Help {
+    type Response = Vec<String>;
+
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn parse(&self, reply: String) -> Result<Self::Response, FocusError> {
+        Ok(reply.lines().map(String::from).collect())
+    }
+}
+
+/// `keymap.custom` -- reads the custom keymap layers when `keycodes` is
+/// `None`, or writes them when it's `Some`, as a flat list of Kaleidoscope
+/// keycodes.
+pub struct KeymapCustom {
+    pub keycodes: Option<Vec<u16>>,
+}
+
+impl Command for KeymapCustom {
+    type Response = Vec<u16>;
+
+    fn name(&self) -> &str {
+        "keymap.custom"
+    }
+
+    fn args(&self) -> Vec<String> {
+        self.keycodes
+            .as_ref()
+            .map(|keycodes| keycodes.iter().map(u16::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    fn parse(&self, reply: String) -> Result<Self::Response, FocusError> {
+        reply
+            .split_whitespace()
+            .map(|n| n.parse().map_err(|_| FocusError::UnexpectedReply(reply.clone())))
+            .collect()
+    }
+}
+
+/// `led.setAll` -- sets every LED on the device to the same RGB color.
+pub struct LedSetAll {
+    pub color: (u8, u8, u8),
+}
+
+impl Command for LedSetAll {
+    type Response = ();
+
+    fn name(&self) -> &str {
+        "led.setAll"
+    }
+
+    fn args(&self) -> Vec<String> {
+        let (r, g, b) = self.color;
+        vec![r.to_string(), g.to_string(), b.to_string()]
+    }
+
+    fn parse(&self, _reply: String) -> Result<Self::Response, FocusError> {
+        Ok(())
+    }
+}
+
+/// `palette` -- reads the device's current color palette as a list of RGB
+/// triples.
+pub struct PaletteGet;
+
+impl Command for PaletteGet {
+    type Response = Vec<(u8, u8, u8)>;
+
+    fn name(&self) -> &str {
+        "palette"
+    }
+
+    fn parse(&self, reply: String) -> Result<Self::Response, FocusError> {
+        let malformed = || FocusError::UnexpectedReply(reply.clone());
+
+        reply
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .chunks(3)
+            .map(|chunk| match chunk {
+                [r, g, b] => {
+                    let r = r.parse().map_err(|_| malformed())?;
+                    let g = g.parse().map_err(|_| malformed())?;
+                    let b = b.parse().map_err(|_| malformed())?;
+                    Ok((r, g, b))
+                }
+                _ => Err(malformed()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keymap_custom_parses_whitespace_separated_keycodes() {
+        let response = KeymapCustom { keycodes: None }
+            .parse(String::from("1 2 3 65535"))
+            .unwrap();
+
+        assert_eq!(response, vec![1, 2, 3, 65535]);
+    }
+
+    #[test]
+    fn keymap_custom_rejects_a_non_numeric_reply() {
+        let result = KeymapCustom { keycodes: None }.parse(String::from("1 nope 3"));
+
+        assert!(matches!(result, Err(FocusError::UnexpectedReply(_))));
+    }
+
+    #[test]
+    fn palette_get_parses_rgb_triples() {
+        let response = PaletteGet.parse(String::from("255 0 0 0 255 0")).unwrap();
+
+        assert_eq!(response, vec![(255, 0, 0), (0, 255, 0)]);
+    }
+
+    #[test]
+    fn palette_get_rejects_a_count_not_a_multiple_of_three() {
+        let result = PaletteGet.parse(String::from("255 0 0 0 255"));
+
+        assert!(matches!(result, Err(FocusError::UnexpectedReply(_))));
+    }
+
+    #[test]
+    fn palette_get_rejects_non_numeric_components() {
+        let result = PaletteGet.parse(String::from("255 nope 0"));
+
+        assert!(matches!(result, Err(FocusError::UnexpectedReply(_))));
+    }
+}
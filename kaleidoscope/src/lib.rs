@@ -14,27 +14,124 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use serialport::SerialPort;
-use std::io::{self, Write};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+pub mod command;
+pub mod registry;
+
+pub use command::Command;
+pub use registry::{DeviceDescriptor, DeviceRegistry};
+
+/// Default safety-net timeout for [`Focus::read_reply`]: how long to keep
+/// waiting for the `.` terminator before giving up on a device that's gone
+/// silent mid-reply.
+const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything that can go wrong while talking to a Kaleidoscope device over
+/// the Focus protocol.
+#[derive(Debug)]
+pub enum FocusError {
+    /// Opening or configuring the serial port failed.
+    ConnectionFailed(serialport::Error),
+    /// A read or write on an otherwise-open port failed.
+    Io(std::io::Error),
+    /// The port went away mid-conversation (e.g. the device was unplugged).
+    Disconnected,
+    /// No candidate device was found to connect to.
+    NoDeviceFound,
+    /// The device on the other end of the port doesn't speak Focus. The
+    /// string is whatever it replied with instead.
+    WrongDevice(String),
+    /// A command's reply didn't parse into the shape that command expects.
+    /// The string is the raw reply that failed to parse.
+    UnexpectedReply(String),
+    /// A device registry config file was malformed. The string is a
+    /// human-readable description of what went wrong.
+    Config(String),
+}
+
+impl fmt::Display for FocusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FocusError::ConnectionFailed(e) => write!(f, "failed to connect to the device: {}", e),
+            FocusError::Io(e) => write!(f, "I/O error while talking to the device: {}", e),
+            FocusError::Disconnected => write!(f, "the device disconnected"),
+            FocusError::NoDeviceFound => write!(f, "no device found to connect to"),
+            FocusError::WrongDevice(reply) => {
+                write!(f, "device does not speak Focus, got: {:?}", reply)
+            }
+            FocusError::UnexpectedReply(reply) => {
+                write!(f, "reply did not have the expected shape, got: {:?}", reply)
+            }
+            FocusError::Config(message) => write!(f, "invalid device registry config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FocusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FocusError::ConnectionFailed(e) => Some(e),
+            FocusError::Io(e) => Some(e),
+            FocusError::Disconnected
+            | FocusError::NoDeviceFound
+            | FocusError::WrongDevice(_)
+            | FocusError::UnexpectedReply(_)
+            | FocusError::Config(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FocusError {
+    fn from(e: std::io::Error) -> Self {
+        FocusError::Io(e)
+    }
+}
+
+impl From<serialport::Error> for FocusError {
+    fn from(e: serialport::Error) -> Self {
+        FocusError::ConnectionFailed(e)
+    }
+}
 
 pub struct Focus {
-    port: Box<dyn SerialPort>,
+    port: BufReader<Box<dyn SerialPort>>,
     chunk_size: usize,
     write_delay: u64,
+    reply_timeout: Duration,
 }
 
-impl From<Box<dyn SerialPort>> for Focus {
-    fn from(port: Box<dyn SerialPort>) -> Self {
-        Self {
-            port,
+/// What a single raw line read off the wire turned out to be, once
+/// [`Focus::classify_reply_line`] has stripped its line ending.
+#[derive(Debug, PartialEq, Eq)]
+enum ReplyLine {
+    /// The `.` end-of-response sentinel.
+    Sentinel,
+    /// An empty line, dropped rather than kept as reply content.
+    Blank,
+    /// A line of actual reply content, with its line ending already removed.
+    Content(String),
+}
+
+impl Focus {
+    /// Wraps an already-open serial port in a `Focus` client.
+    ///
+    /// This returns a `Result` but performs no setup of its own and always
+    /// succeeds; the handshake that confirms the device actually speaks
+    /// Focus is the separate [`Focus::verify`] step.
+    pub fn from(port: Box<dyn SerialPort>) -> Result<Self, FocusError> {
+        Ok(Self {
+            port: BufReader::new(port),
             chunk_size: 32,
             write_delay: 500,
-        }
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+        })
     }
-}
 
-impl Focus {
     pub fn chunk_size(&mut self, chunk_size: usize) -> &Self {
         self.chunk_size = chunk_size;
         self
@@ -45,17 +142,80 @@ impl Focus {
         self
     }
 
-    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+    /// Overall safety-net timeout for [`Focus::read_reply`]: how long to
+    /// keep waiting for the `.` terminator before giving up.
+    pub fn reply_timeout(&mut self, reply_timeout: Duration) -> &Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    pub fn flush(&mut self) -> Result<(), FocusError> {
         self.request(String::from(" "), None)?;
         self.read_reply()?;
         Ok(())
     }
 
+    /// Confirms that whatever is on the other end of the port actually
+    /// speaks Focus, by sending the `version` command and sanity-checking
+    /// the reply, rather than trusting that a matching USB vid/pid means a
+    /// matching firmware. Returns the version string on success.
+    ///
+    /// Flushes first, since this is typically the first traffic on a
+    /// freshly opened port and any output the device had buffered before we
+    /// started listening would otherwise land as (and corrupt) the reply.
+    pub fn verify(&mut self) -> Result<String, FocusError> {
+        self.flush()?;
+        self.request(String::from("version"), None)?;
+        let reply = self.read_reply()?;
+
+        if Self::looks_like_focus_reply(&reply) {
+            Ok(reply)
+        } else {
+            Err(FocusError::WrongDevice(reply))
+        }
+    }
+
+    /// A Kaleidoscope `version` reply is a dotted version number (e.g.
+    /// `1.0.0`), optionally followed by more text on the same line. This
+    /// only confirms the first token looks like a version number and that
+    /// the whole reply is printable ASCII or whitespace, so it
+    /// rejects a chatty-but-wrong device (a bootloader prompt, an unrelated
+    /// CDC device echoing a banner) as well as a silent or binary-garbage
+    /// one -- it isn't a guarantee the device is genuinely Kaleidoscope.
+    fn looks_like_focus_reply(reply: &str) -> bool {
+        let version_token = reply
+            .lines()
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+
+        let looks_like_version = version_token.contains('.')
+            && version_token
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == '.');
+
+        looks_like_version
+            && reply
+                .chars()
+                .all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace())
+    }
+
+    /// Sends a typed [`Command`] and parses its reply into the command's
+    /// structured response type. Unknown or ad hoc commands can still go
+    /// through [`Focus::request`] and [`Focus::read_reply`] directly.
+    pub fn send<C: Command>(&mut self, cmd: C) -> Result<C::Response, FocusError> {
+        self.request(cmd.name().to_string(), Some(cmd.args()))?;
+        let reply = self.read_reply()?;
+        cmd.parse(reply)
+    }
+
     pub fn request(
         &mut self,
         command: String,
         args: Option<Vec<String>>,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), FocusError> {
         self.request_with_progress(command, args, |_| {}, |_| {})
     }
 
@@ -65,60 +225,162 @@ impl Focus {
         args: Option<Vec<String>>,
         set_length: FL,
         progress: FP,
-    ) -> Result<(), std::io::Error>
+    ) -> Result<(), FocusError>
     where
         FL: Fn(usize),
         FP: Fn(usize),
     {
         let request = [vec![command], args.unwrap_or_default()].concat().join(" ") + "\n";
-        self.port.write_data_terminal_ready(true)?;
+        self.port.get_mut().write_data_terminal_ready(true)?;
 
         set_length(request.len());
 
         for c in request.as_bytes().chunks(self.chunk_size) {
             progress(c.len());
-            self.port.write_all(c)?;
+            self.port.get_mut().write_all(c)?;
             thread::sleep(Duration::from_millis(self.write_delay));
         }
 
         Ok(())
     }
 
-    pub fn read_reply(&mut self) -> Result<String, std::io::Error> {
-        let mut buffer: Vec<u8> = vec![0; 1024];
-        let mut reply = vec![];
-
-        self.port.read_data_set_ready()?;
-        self.wait_for_data()?;
+    /// Reads a Focus reply line by line, stopping as soon as the protocol's
+    /// `.` end-of-response sentinel line is seen, rather than waiting out a
+    /// read timeout on every single command. A read timeout on the
+    /// underlying port is expected while waiting for more data to arrive;
+    /// `reply_timeout` is the overall safety net that gives up if the `.`
+    /// sentinel never shows up at all.
+    pub fn read_reply(&mut self) -> Result<String, FocusError> {
+        let start = Instant::now();
+        let mut lines = vec![];
+        let mut buf: Vec<u8> = vec![];
 
         loop {
-            match self.port.read(buffer.as_mut_slice()) {
-                Ok(t) => {
-                    reply.extend(&buffer[..t]);
+            match self.port.read_until(b'\n', &mut buf) {
+                Ok(0) => return Err(FocusError::Disconnected),
+                Ok(_) if buf.ends_with(b"\n") => {
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    match Self::classify_reply_line(&line) {
+                        ReplyLine::Sentinel => break,
+                        ReplyLine::Blank => {}
+                        ReplyLine::Content(line) => lines.push(line),
+                    }
+                    buf.clear();
                 }
+                // Got some bytes but not a full line yet -- keep the
+                // partial line in `buf` and read more, rather than
+                // discarding it.
+                Ok(_) => {}
                 Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                    break;
-                }
-                Err(e) => {
-                    return Err(e);
+                    if start.elapsed() >= self.reply_timeout {
+                        return Err(FocusError::Io(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out waiting for the Focus reply terminator",
+                        )));
+                    }
                 }
+                Err(e) => return Err(e.into()),
             }
-
-            thread::sleep(Duration::from_millis(self.write_delay));
         }
 
-        Ok(String::from_utf8_lossy(&reply)
-            .to_string()
-            .lines()
-            .filter(|l| !l.is_empty() && *l != ".")
-            .collect::<Vec<&str>>()
-            .join("\n"))
+        Ok(lines.join("\n"))
     }
 
-    fn wait_for_data(&mut self) -> Result<(), std::io::Error> {
-        while self.port.bytes_to_read()? == 0 {
-            thread::sleep(Duration::from_millis(self.write_delay));
+    /// Classifies a single raw line read off the wire (still carrying its
+    /// `\r`/`\n` line ending) for [`Focus::read_reply`]: is it the protocol's
+    /// `.` end-of-response sentinel, a blank line to be dropped, or a line of
+    /// actual content to keep?
+    fn classify_reply_line(raw: &str) -> ReplyLine {
+        let line = raw.trim_end_matches(['\r', '\n']);
+
+        if line == "." {
+            ReplyLine::Sentinel
+        } else if line.is_empty() {
+            ReplyLine::Blank
+        } else {
+            ReplyLine::Content(line.to_string())
         }
-        Ok(())
+    }
+
+    /// Switches into streaming mode: hands the port off to a dedicated
+    /// reader thread that continuously reads lines and pushes them over the
+    /// returned channel, so a caller can watch unsolicited output (log
+    /// lines, key events) instead of issuing one request and exiting. This
+    /// consumes the `Focus`, since once the reader thread owns the port
+    /// there's no longer a request/reply flow to interleave it with.
+    pub fn stream(self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let mut port = self.port;
+
+        thread::spawn(move || {
+            let mut buf: Vec<u8> = vec![];
+
+            loop {
+                match port.read_until(b'\n', &mut buf) {
+                    Ok(0) => break,
+                    Ok(_) if buf.ends_with(b"\n") => {
+                        let line = String::from_utf8_lossy(&buf).into_owned();
+                        let line = line.trim_end_matches(['\r', '\n']);
+                        if !line.is_empty() && tx.send(line.to_string()).is_err() {
+                            break;
+                        }
+                        buf.clear();
+                    }
+                    // Got some bytes but not a full line yet -- keep the
+                    // partial line in `buf` and read more, rather than
+                    // discarding it.
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(_) => break,
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Focus, ReplyLine};
+
+    #[test]
+    fn looks_like_focus_reply_accepts_a_version_number() {
+        assert!(Focus::looks_like_focus_reply("1.0.0"));
+        assert!(Focus::looks_like_focus_reply("1.0.0\nmore lines\n"));
+    }
+
+    #[test]
+    fn looks_like_focus_reply_rejects_empty_and_chatty_replies() {
+        assert!(!Focus::looks_like_focus_reply(""));
+        assert!(!Focus::looks_like_focus_reply(
+            "Bootloader Mode - select an option"
+        ));
+        assert!(!Focus::looks_like_focus_reply("\u{0}\u{1}\u{2}"));
+    }
+
+    #[test]
+    fn classify_reply_line_normalizes_crlf_line_endings() {
+        assert_eq!(
+            Focus::classify_reply_line("hello\r\n"),
+            ReplyLine::Content("hello".to_string())
+        );
+        assert_eq!(
+            Focus::classify_reply_line("hello\n"),
+            ReplyLine::Content("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn classify_reply_line_drops_blank_lines() {
+        assert_eq!(Focus::classify_reply_line("\n"), ReplyLine::Blank);
+        assert_eq!(Focus::classify_reply_line("\r\n"), ReplyLine::Blank);
+        assert_eq!(Focus::classify_reply_line(""), ReplyLine::Blank);
+    }
+
+    #[test]
+    fn classify_reply_line_detects_the_sentinel() {
+        assert_eq!(Focus::classify_reply_line(".\n"), ReplyLine::Sentinel);
+        assert_eq!(Focus::classify_reply_line(".\r\n"), ReplyLine::Sentinel);
     }
 }
@@ -0,0 +1,171 @@
+// kaleidoscope -- Talk with Kaleidoscope powered devices
+// Copyright (C) 2022  Keyboard.io, Inc.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::FocusError;
+use regex::Regex;
+use serde::Deserialize;
+
+/// A single known (or user-described) device: any field left unset matches
+/// anything, so a descriptor can narrow on vid/pid alone, product name
+/// alone, or any combination.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceDescriptor {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// A regex matched against the USB product string, e.g. `"Atreus.*"`.
+    pub name: Option<String>,
+}
+
+impl DeviceDescriptor {
+    fn matches(&self, vid: u16, pid: u16, product: Option<&str>) -> bool {
+        if self.vid.is_some_and(|v| v != vid) {
+            return false;
+        }
+        if self.pid.is_some_and(|p| p != pid) {
+            return false;
+        }
+        match &self.name {
+            None => true,
+            Some(pattern) => product.is_some_and(|product| {
+                Regex::new(pattern)
+                    .map(|re| re.is_match(product))
+                    .unwrap_or(false)
+            }),
+        }
+    }
+}
+
+/// The set of devices `focus-send` will consider talking to. Seeded with the
+/// Keyboardio boards this crate has always known about, and extensible at
+/// runtime with entries loaded from a config file or passed on the command
+/// line, so a new board (or a clone, or a dev build with made-up ids)
+/// doesn't require patching the source.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeviceRegistry {
+    #[serde(default, rename = "device")]
+    descriptors: Vec<DeviceDescriptor>,
+}
+
+impl DeviceRegistry {
+    /// The Keyboardio boards this crate has always recognized.
+    pub fn builtin() -> Self {
+        Self {
+            descriptors: vec![
+                // Keyboardio Model100
+                DeviceDescriptor {
+                    vid: Some(0x3496),
+                    pid: Some(0x0006),
+                    name: None,
+                },
+                // Keyboardio Atreus
+                DeviceDescriptor {
+                    vid: Some(0x1209),
+                    pid: Some(0x2303),
+                    name: None,
+                },
+                // Keyboardio Model01
+                DeviceDescriptor {
+                    vid: Some(0x1209),
+                    pid: Some(0x2301),
+                    name: None,
+                },
+            ],
+        }
+    }
+
+    /// Parses a TOML config file of `[[device]]` tables into a registry.
+    pub fn from_toml(contents: &str) -> Result<Self, FocusError> {
+        toml::from_str(contents).map_err(|e| FocusError::Config(e.to_string()))
+    }
+
+    /// Folds another registry's descriptors into this one.
+    pub fn merge(&mut self, other: DeviceRegistry) {
+        self.descriptors.extend(other.descriptors);
+    }
+
+    /// Adds a single descriptor, e.g. one built from `--vid`/`--pid`
+    /// command-line overrides.
+    pub fn push(&mut self, descriptor: DeviceDescriptor) {
+        self.descriptors.push(descriptor);
+    }
+
+    /// Whether any descriptor in the registry matches this vid/pid/product.
+    pub fn matches(&self, vid: u16, pid: u16, product: Option<&str>) -> bool {
+        self.descriptors
+            .iter()
+            .any(|d| d.matches(vid, pid, product))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeviceDescriptor, DeviceRegistry};
+
+    #[test]
+    fn vid_pid_descriptor_matches_only_that_pair() {
+        let descriptor = DeviceDescriptor {
+            vid: Some(0x1209),
+            pid: Some(0x2303),
+            name: None,
+        };
+
+        assert!(descriptor.matches(0x1209, 0x2303, None));
+        assert!(!descriptor.matches(0x1209, 0x2301, None));
+        assert!(!descriptor.matches(0x3496, 0x2303, None));
+    }
+
+    #[test]
+    fn name_descriptor_matches_by_regex_against_the_product_string() {
+        let descriptor = DeviceDescriptor {
+            vid: None,
+            pid: None,
+            name: Some("^Atreus.*".to_string()),
+        };
+
+        assert!(descriptor.matches(0xffff, 0xffff, Some("Atreus Clone")));
+        assert!(!descriptor.matches(0xffff, 0xffff, Some("Some Other Board")));
+        assert!(!descriptor.matches(0xffff, 0xffff, None));
+    }
+
+    #[test]
+    fn builtin_registry_matches_the_known_keyboardio_boards() {
+        let registry = DeviceRegistry::builtin();
+
+        assert!(registry.matches(0x3496, 0x0006, None)); // Model100
+        assert!(registry.matches(0x1209, 0x2303, None)); // Atreus
+        assert!(registry.matches(0x1209, 0x2301, None)); // Model01
+        assert!(!registry.matches(0xdead, 0xbeef, None));
+    }
+
+    #[test]
+    fn from_toml_merges_into_the_builtin_registry() {
+        let toml = r#"
+            [[device]]
+            vid = 0xdead
+            pid = 0xbeef
+        "#;
+
+        let mut registry = DeviceRegistry::builtin();
+        registry.merge(DeviceRegistry::from_toml(toml).unwrap());
+
+        assert!(registry.matches(0xdead, 0xbeef, None));
+        assert!(registry.matches(0x1209, 0x2303, None));
+    }
+
+    #[test]
+    fn from_toml_rejects_malformed_config() {
+        assert!(DeviceRegistry::from_toml("not valid toml [[[").is_err());
+    }
+}
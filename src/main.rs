@@ -15,9 +15,8 @@
 
 use clap::Parser;
 use indicatif::ProgressBar;
-use serialport::SerialPort;
-use std::io::{self, Write};
-use std::thread;
+use kaleidoscope::{DeviceDescriptor, DeviceRegistry, Focus, FocusError};
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Parser)]
@@ -34,172 +33,186 @@ struct Cli {
     device: Option<String>,
     #[arg(short, long, help = "Operate quietly", default_value = "false")]
     quiet: bool,
+    #[arg(
+        short,
+        long,
+        help = "Stream unsolicited output from the device instead of sending a command",
+        conflicts_with_all = ["command", "args"]
+    )]
+    monitor: bool,
+    #[arg(
+        long,
+        value_parser = parse_u16,
+        help = "Also consider devices with this USB vendor id (decimal or 0x-prefixed hex)"
+    )]
+    vid: Option<u16>,
+    #[arg(
+        long,
+        value_parser = parse_u16,
+        help = "Also consider devices with this USB product id (decimal or 0x-prefixed hex)"
+    )]
+    pid: Option<u16>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "TOML file of additional [[device]] entries (default: $HOME/.config/focus-send/devices.toml)"
+    )]
+    config: Option<PathBuf>,
 
-    command: String,
+    #[arg(required_unless_present = "monitor")]
+    command: Option<String>,
     args: Vec<String>,
 }
 
+fn parse_u16(s: &str) -> Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
 fn main() {
-    let opts = Cli::parse();
-    let device = opts.device().unwrap_or_else(|| {
-        eprintln!("No device found to connect to");
+    if let Err(e) = run() {
+        eprintln!("{}", e);
         ::std::process::exit(1);
-    });
-
-    let mut port = serialport::new(&device, 11520)
-        .timeout(Duration::from_millis(100))
-        .open()
-        .unwrap_or_else(|e| {
-            eprintln!("Failed to open \"{}\". Error: {}", &device, e);
-            ::std::process::exit(1);
-        });
-
-    flush(&mut port);
-
-    send_request(&mut port, !opts.quiet, opts.command, opts.args)
-        .expect("failed to send the request to the keyboard");
-
-    wait_for_data(&*port);
-
-    let reply = read_reply(&mut port).expect("failed to read the reply");
-    println!("{}", reply);
+    }
 }
 
-impl Cli {
-    fn device(&self) -> Option<String> {
-        #[derive(PartialEq)]
-        struct DeviceDescriptor {
-            vid: u16,
-            pid: u16,
-        }
-        let supported_keyboards = [
-            // Keyboardio Model100
-            DeviceDescriptor {
-                vid: 0x3496,
-                pid: 0x0006,
-            },
-            // Keyboardio Atreus
-            DeviceDescriptor {
-                vid: 0x1209,
-                pid: 0x2303,
-            },
-            // Keyboardio Model01
-            DeviceDescriptor {
-                vid: 0x1209,
-                pid: 0x2301,
-            },
-        ];
-
-        // If we had a device explicitly specified, use that.
-        if let Some(device) = &self.device {
-            return Some(device.to_string());
-        }
+fn run() -> Result<(), FocusError> {
+    let opts = Cli::parse();
+    let focus = opts.connect()?;
 
-        // Otherwise list the serial ports, and return the first USB serial port
-        // that has a vid/pid that matches any of the Keyboardio devices.
-        serialport::available_ports()
-            .ok()?
-            .iter()
-            .filter_map(|p| match &p.port_type {
-                serialport::SerialPortType::UsbPort(port_info) => {
-                    struct MinimalPortInfo {
-                        ids: DeviceDescriptor,
-                        port: String,
-                    }
-                    Some(MinimalPortInfo {
-                        ids: DeviceDescriptor {
-                            vid: port_info.vid,
-                            pid: port_info.pid,
-                        },
-                        port: p.port_name.to_string(),
-                    })
-                }
-                _ => None,
-            })
-            .find_map(|p| supported_keyboards.contains(&p.ids).then(|| p.port))
+    if opts.monitor {
+        return monitor(focus);
     }
-}
 
-// Send an empty command, and consume any replies. This should clear any pending
-// commands or output.
-fn flush(port: &mut Box<dyn SerialPort>) {
-    send_request(port, false, String::from(" "), vec![]).expect("failed to send an empty command");
-    wait_for_data(&**port);
-    read_reply(port).expect("failed to flush the device");
+    send_command(focus, opts)
 }
 
-fn send_request(
-    port: &mut Box<dyn SerialPort>,
-    with_progress: bool,
-    command: String,
-    args: Vec<String>,
-) -> Result<(), std::io::Error> {
-    let request = [vec![command], args.clone()].concat().join(" ") + "\n";
+fn send_command(mut focus: Focus, opts: Cli) -> Result<(), FocusError> {
+    focus.flush()?;
+
+    let with_progress = !opts.quiet && !opts.args.is_empty();
+    let pb = ProgressBar::hidden();
+    focus.request_with_progress(
+        opts.command.expect("command is required unless --monitor is set"),
+        Some(opts.args),
+        |len| {
+            if with_progress {
+                pb.set_length(len.try_into().unwrap());
+                pb.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+            }
+        },
+        |n| pb.inc(n.try_into().unwrap()),
+    )?;
+    pb.finish_and_clear();
 
-    port.write_data_terminal_ready(true)?;
+    let reply = focus.read_reply()?;
+    println!("{}", reply);
 
-    let pb = if with_progress && !args.is_empty() {
-        ProgressBar::new(request.len().try_into().unwrap())
-    } else {
-        ProgressBar::hidden()
-    };
+    Ok(())
+}
 
-    for c in request.as_bytes().chunks(64) {
-        pb.inc(c.len().try_into().unwrap());
-        port.write_all(c)?;
-        thread::sleep(Duration::from_millis(50));
+fn monitor(focus: Focus) -> Result<(), FocusError> {
+    for line in focus.stream() {
+        println!("{}", line);
     }
 
-    pb.finish_and_clear();
     Ok(())
 }
 
-fn wait_for_data(port: &dyn SerialPort) {
-    while port.bytes_to_read().expect("Error calling bytes_to_read") == 0 {
-        thread::sleep(Duration::from_millis(100));
-    }
+/// `$HOME/.config/focus-send/devices.toml`, if `$HOME` is set. Used as the
+/// default device registry config path when `--config` isn't given.
+fn default_config_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/focus-send/devices.toml"))
 }
 
-fn read_reply(port: &mut Box<dyn SerialPort>) -> Result<String, std::io::Error> {
-    let mut buffer: Vec<u8> = vec![0; 1024];
-    let mut reply = vec![];
-
-    port.read_data_set_ready()?;
-
-    loop {
-        match port.read(buffer.as_mut_slice()) {
-            Ok(t) => {
-                reply.extend(&buffer[..t]);
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                break;
-            }
-            Err(e) => {
-                return Err(e);
+impl Cli {
+    /// Builds the device registry this run should match against: the
+    /// built-in Keyboardio descriptors, merged with entries from `--config`
+    /// (or the default config path) and a descriptor built from
+    /// `--vid`/`--pid`.
+    fn registry(&self) -> Result<DeviceRegistry, FocusError> {
+        let mut registry = DeviceRegistry::builtin();
+
+        if let Some(path) = &self.config {
+            // The user asked for this file explicitly, so a read failure is
+            // a real error, not something to fall back silently from.
+            let contents = std::fs::read_to_string(path).map_err(FocusError::from)?;
+            registry.merge(DeviceRegistry::from_toml(&contents)?);
+        } else if let Some(path) = default_config_path() {
+            if let Ok(contents) = std::fs::read_to_string(&path) {
+                registry.merge(DeviceRegistry::from_toml(&contents)?);
             }
         }
 
-        thread::sleep(Duration::from_millis(100));
+        if self.vid.is_some() || self.pid.is_some() {
+            registry.push(DeviceDescriptor {
+                vid: self.vid,
+                pid: self.pid,
+                name: None,
+            });
+        }
+
+        Ok(registry)
     }
 
-    Ok(cleanup_reply(String::from_utf8_lossy(&reply).to_string()))
-}
+    /// Device paths worth trying, in order. If a device was explicitly given
+    /// on the command line, it's the only candidate; otherwise every USB
+    /// serial port whose vid/pid/product matches the device registry is a
+    /// candidate, since a match doesn't guarantee the firmware on the other
+    /// end actually speaks Focus (e.g. a board stuck in the bootloader).
+    fn device_candidates(&self) -> Result<Vec<String>, FocusError> {
+        // If we had a device explicitly specified, use that.
+        if let Some(device) = &self.device {
+            return Ok(vec![device.to_string()]);
+        }
 
-fn cleanup_reply(reply: String) -> String {
-    reply
-        .lines()
-        .filter(|l| !l.is_empty() && *l != ".")
-        .collect::<Vec<&str>>()
-        .join("\n")
-}
+        let registry = self.registry()?;
+
+        // Otherwise list the serial ports, and return every USB serial port
+        // that the registry recognizes.
+        Ok(serialport::available_ports()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|p| match &p.port_type {
+                serialport::SerialPortType::UsbPort(port_info) => Some((
+                    port_info.vid,
+                    port_info.pid,
+                    port_info.product.clone(),
+                    p.port_name.to_string(),
+                )),
+                _ => None,
+            })
+            .filter(|(vid, pid, product, _)| registry.matches(*vid, *pid, product.as_deref()))
+            .map(|(_, _, _, port)| port)
+            .collect())
+    }
+
+    /// Tries every device candidate in turn, opening the port and verifying
+    /// that it actually speaks Focus, falling through to the next candidate
+    /// if it doesn't. Returns the last error encountered if none work.
+    fn connect(&self) -> Result<Focus, FocusError> {
+        let candidates = self.device_candidates()?;
+        if candidates.is_empty() {
+            return Err(FocusError::NoDeviceFound);
+        }
+        let mut last_err = FocusError::NoDeviceFound;
+
+        for device in candidates {
+            let attempt = serialport::new(&device, 11520)
+                .timeout(Duration::from_millis(100))
+                .open()
+                .map_err(FocusError::from)
+                .and_then(Focus::from)
+                .and_then(|mut focus| focus.verify().map(|_| focus));
+
+            match attempt {
+                Ok(focus) => return Ok(focus),
+                Err(e) => last_err = e,
+            }
+        }
 
-#[cfg(test)]
-mod test {
-    #[test]
-    fn cleanup_reply() {
-        assert_eq!(
-            super::cleanup_reply(String::from("line1\nline2\r\nline3")),
-            "line1\nline2\nline3"
-        );
+        Err(last_err)
     }
 }